@@ -5,65 +5,425 @@
 
 pub mod shaders;
 
-use log::info;
+use std::rc::Rc;
+
+use log::{info, warn};
 
 use nalgebra as na;
 
 use glium::{uniform, Surface, Texture2d};
 
-use crate::shader::{self, InstanceInput, ToUniforms};
-use crate::{
-    basic_obj, screen_quad, BasicObj, Camera, Context, DrawError, Drawable, Instancing, Light,
-    Mesh, ScreenQuad,
-};
+use crate::shader::preprocessor::{FeatureFlags, ProgramCache, ProgramKey};
+use crate::shader::{self, ToUniforms};
+use crate::{screen_quad, Camera, Context, DrawError, Light, ScreenQuad};
 
+use crate::pipeline::render_graph::{GraphError, Node, RenderGraph, SlotFormat};
 use crate::pipeline::render_pass::{
     CompositionPassComponent, HasCompositionPassParams, HasScenePassParams, RenderPassComponent,
     ScenePassComponent,
 };
+use crate::pipeline::uniform_block::UniformBlock;
 
 pub use crate::CreationError;
 
+/// Filtering mode used when sampling the main light's shadow map.
+#[derive(Debug, Clone)]
+pub enum ShadowFilter {
+    /// A single hard comparison tap, producing aliased shadow edges.
+    Hard,
+
+    /// Percentage-closer filtering: average `taps` comparisons spread over a
+    /// Poisson-disc kernel, rotated per pixel to trade banding for noise.
+    Pcf { taps: usize },
+
+    /// Percentage-closer soft shadows: estimate the penumbra width from an
+    /// average blocker depth, then run PCF with the kernel radius scaled by it.
+    Pcss {
+        blocker_taps: usize,
+        light_size: f32,
+    },
+}
+
+impl Default for ShadowFilter {
+    fn default() -> Self {
+        ShadowFilter::Hard
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub light_min_threshold: f32,
+
+    /// How the main light's shadow map is filtered.
+    pub shadow_filter: ShadowFilter,
+
+    /// Dimensions `(x, y, z)` of the clustered light-culling grid. The view
+    /// frustum is split into this many clusters, with exponential depth slices,
+    /// and each cluster keeps its own list of affecting lights.
+    pub cluster_grid_size: (u32, u32, u32),
+
+    /// When set, use a compact G-buffer layout: normals are octahedral-encoded
+    /// into an `RG16` target and the explicit world-position target is dropped,
+    /// with world-space position reconstructed from depth in the light pass.
+    pub compact_gbuffer: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             light_min_threshold: 0.02,
+            shadow_filter: ShadowFilter::default(),
+            cluster_grid_size: (16, 9, 24),
+            compact_gbuffer: false,
         }
     }
 }
 
-const NUM_TEXTURES: usize = 2;
+/// Maximum number of light references stored per cluster. Excess lights for a
+/// very crowded cluster are dropped, which only dims that cluster slightly.
+const MAX_LIGHTS_PER_CLUSTER: usize = 256;
+
+/// Upper bound on the number of additional lights the culling pass considers in
+/// a single frame. Lights beyond this are ignored by the cluster grid, and so
+/// are not shaded by the clustered light pass.
+const MAX_CULLED_LIGHTS: usize = 1024;
+
+/// One additional light as laid out for the GPU: three `vec4`s holding
+/// `(position.xyz, radius)`, `(color.rgb, _)`, and `(attenuation.xyz, _)`. The
+/// compute cull only reads the first `vec4`; the light pass reads all three.
+type GpuLight = [[f32; 4]; 3];
+
+/// Clustered light culling: partitions the view frustum into a 3D grid of
+/// clusters, recomputes each cluster's world-space AABB every frame (they
+/// depend on the camera), and runs a compute shader that appends the indices of
+/// the lights whose bounding sphere intersects each cluster into a shared
+/// light-index buffer.
+///
+/// The light/composition shader then reads only the lights for the fragment's
+/// cluster, instead of relying on additive geometry overdraw.
+struct ClusterGrid {
+    size: (u32, u32, u32),
+
+    /// The additional lights, uploaded each frame. Indexed both by the compute
+    /// cull (for the bounding sphere) and by the light pass (for shading).
+    lights: glium::uniforms::UniformBuffer<[GpuLight]>,
+
+    /// World-space AABBs of every cluster, recomputed each frame. Two `vec4`s
+    /// (min, max) per cluster.
+    cluster_aabbs: glium::uniforms::UniformBuffer<[[f32; 4]]>,
+
+    /// Flat per-cluster light index lists.
+    light_indices: glium::uniforms::UniformBuffer<[u32]>,
+
+    /// `(offset, count)` into `light_indices` for every cluster.
+    light_grid: glium::uniforms::UniformBuffer<[[u32; 2]]>,
+
+    cull_program: glium::program::ComputeShader,
+}
+
+impl ClusterGrid {
+    fn create<F: glium::backend::Facade>(
+        facade: &F,
+        size: (u32, u32, u32),
+    ) -> Result<ClusterGrid, CreationError> {
+        // A zero in any dimension would produce zero-sized buffers and a
+        // degenerate dispatch, so clamp every axis to at least one cluster.
+        let size = (size.0.max(1), size.1.max(1), size.2.max(1));
+        // Widen to `usize` so a large configured grid cannot wrap the count.
+        let num_clusters = size.0 as usize * size.1 as usize * size.2 as usize;
+
+        // The buffers are bound as std430 shader-storage blocks (not uniform
+        // blocks), so they are not subject to `GL_MAX_UNIFORM_BLOCK_SIZE` and
+        // are sized to the configured grid rather than a fixed maximum.
+        let lights = glium::uniforms::UniformBuffer::empty_unsized(
+            facade,
+            MAX_CULLED_LIGHTS * std::mem::size_of::<GpuLight>(),
+        )?;
+        let cluster_aabbs = glium::uniforms::UniformBuffer::empty_unsized(
+            facade,
+            2 * num_clusters * std::mem::size_of::<[f32; 4]>(),
+        )?;
+        let light_indices = glium::uniforms::UniformBuffer::empty_unsized(
+            facade,
+            num_clusters * MAX_LIGHTS_PER_CLUSTER * std::mem::size_of::<u32>(),
+        )?;
+        let light_grid = glium::uniforms::UniformBuffer::empty_unsized(
+            facade,
+            num_clusters * std::mem::size_of::<[u32; 2]>(),
+        )?;
+
+        let cull_program =
+            glium::program::ComputeShader::from_source(facade, shaders::cluster_cull_compute())?;
+
+        Ok(ClusterGrid {
+            size,
+            lights,
+            cluster_aabbs,
+            light_indices,
+            light_grid,
+            cull_program,
+        })
+    }
+
+    fn num_clusters(&self) -> u32 {
+        self.size.0 * self.size.1 * self.size.2
+    }
+
+    /// Recompute the world-space cluster AABBs for the current camera. Depth
+    /// slices are distributed exponentially as `near * (far / near)^(k / n)`.
+    fn recompute_aabbs(&mut self, camera: &Camera) {
+        shaders::fill_cluster_aabbs(self.size, camera, &mut self.cluster_aabbs);
+    }
+
+    /// Dispatch the culling compute shader over the grid, filling
+    /// `light_indices` and `light_grid` from the given lights.
+    ///
+    /// The very next draw (`light_pass`'s additional-lights quad) reads
+    /// `light_indices`/`light_grid` back in its fragment shader, but `glium`
+    /// does not insert a memory barrier between a compute dispatch and a
+    /// later draw on its own. Without one, those fragment reads can race this
+    /// dispatch's shader-storage writes, so a `SHADER_STORAGE_BARRIER_BIT`
+    /// barrier is issued before returning.
+    fn cull<F: glium::backend::Facade>(&mut self, facade: &F, lights: &[GpuLight]) {
+        if lights.len() > MAX_CULLED_LIGHTS {
+            warn!(
+                "{} additional lights exceeds the cluster-cull limit of {}; \
+                 the excess will not be shaded",
+                lights.len(),
+                MAX_CULLED_LIGHTS,
+            );
+        }
+        let num_lights = lights.len().min(MAX_CULLED_LIGHTS);
+
+        if num_lights > 0 {
+            self.lights
+                .slice(..num_lights)
+                .unwrap()
+                .write(&lights[..num_lights]);
+        }
+
+        self.cull_program.execute(
+            uniform! {
+                num_lights: num_lights as u32,
+                num_clusters: self.num_clusters(),
+                grid_size: [self.size.0, self.size.1, self.size.2],
+                Lights: &self.lights,
+                ClusterAabbs: &self.cluster_aabbs,
+                LightIndices: &self.light_indices,
+                LightGrid: &self.light_grid,
+            },
+            self.size.0,
+            self.size.1,
+            self.size.2,
+        );
+
+        // Block the subsequent draw on the visibility of this dispatch's
+        // `LightIndices`/`LightGrid` writes, since nothing else does.
+        unsafe {
+            facade.get_context().exec_in_context(|| {
+                glium::gl::MemoryBarrier(glium::gl::SHADER_STORAGE_BARRIER_BIT);
+            });
+        }
+    }
+
+    /// Bind the lights and the per-cluster lists produced by the last `cull` so
+    /// a draw's fragment shader can shade only the lights reaching its cluster.
+    fn cluster_uniforms(&self) -> impl ToUniforms + '_ {
+        plain_uniforms! {
+            grid_size: [self.size.0, self.size.1, self.size.2],
+            Lights: &self.lights,
+            LightGrid: &self.light_grid,
+            LightIndices: &self.light_indices,
+        }
+    }
+}
+
+/// Precomputed Poisson-disc sample offsets in `[-1, 1]^2`, used as the PCF
+/// kernel. Each tap is rotated per pixel by a random angle before sampling.
+const SHADOW_POISSON_KERNEL: [[f32; 2]; 16] = [
+    [-0.942_016_2, -0.399_062_2],
+    [0.945_586_1, -0.768_907_4],
+    [-0.094_184_1, -0.929_388_7],
+    [0.344_959_4, 0.293_877_6],
+    [-0.915_885_9, 0.457_714_3],
+    [-0.815_442_4, -0.879_124_5],
+    [-0.382_775_0, 0.276_768_1],
+    [0.974_843_4, 0.756_825_3],
+    [0.443_233_3, -0.975_285_3],
+    [0.537_429_8, -0.473_734_1],
+    [-0.264_969_3, -0.418_930_3],
+    [0.791_975_3, 0.190_901_6],
+    [-0.241_888_1, 0.997_065_4],
+    [-0.814_099_8, 0.914_375_8],
+    [0.199_841_5, 0.786_413_2],
+    [0.143_831_3, -0.141_007_6],
+];
+
+/// Clamp a filter's requested tap counts into `1..=SHADOW_POISSON_KERNEL.len()`,
+/// so the GLSL sampling loop can never index past the kernel we upload and never
+/// averages over zero taps.
+fn clamp_shadow_filter(filter: &ShadowFilter) -> ShadowFilter {
+    let clamp_taps = |taps: usize| taps.clamp(1, SHADOW_POISSON_KERNEL.len());
+
+    match *filter {
+        ShadowFilter::Hard => ShadowFilter::Hard,
+        ShadowFilter::Pcf { taps } => ShadowFilter::Pcf {
+            taps: clamp_taps(taps),
+        },
+        ShadowFilter::Pcss {
+            blocker_taps,
+            light_size,
+        } => ShadowFilter::Pcss {
+            blocker_taps: clamp_taps(blocker_taps),
+            light_size,
+        },
+    }
+}
+
+/// The feature flags selecting the main light's shadow-filter/G-buffer-layout
+/// permutation, used as the `ProgramCache` key so the same permutation isn't
+/// rebuilt across `DeferredShading` instances that share a cache.
+fn main_light_flags(have_shadows: bool, filter: &ShadowFilter, compact_gbuffer: bool) -> FeatureFlags {
+    let mut flags = FeatureFlags::new();
+
+    if have_shadows {
+        flags = flags.enable("HAVE_SHADOWS");
+        flags = match *filter {
+            ShadowFilter::Hard => flags,
+            ShadowFilter::Pcf { taps } => flags.define("SHADOW_FILTER_PCF_TAPS", taps.to_string()),
+            ShadowFilter::Pcss {
+                blocker_taps,
+                light_size,
+            } => flags
+                .define("SHADOW_FILTER_PCSS_BLOCKER_TAPS", blocker_taps.to_string())
+                .define("SHADOW_FILTER_PCSS_LIGHT_SIZE", light_size.to_string()),
+        };
+    }
+
+    if compact_gbuffer {
+        flags = flags.enable("COMPACT_GBUFFER");
+    }
+
+    flags
+}
+
+/// The feature flags for the additional-lights permutation.
+fn additional_light_flags(compact_gbuffer: bool) -> FeatureFlags {
+    let flags = FeatureFlags::new();
+
+    if compact_gbuffer {
+        flags.enable("COMPACT_GBUFFER")
+    } else {
+        flags
+    }
+}
+
+/// Build the `ProgramCache` key for `name`'s permutation under `flags`.
+fn permutation_key(name: &str, flags: &FeatureFlags) -> ProgramKey {
+    let preamble = flags.preamble();
+    ProgramKey {
+        vertex: format!("{}::vertex\n{}", name, preamble),
+        fragment: format!("{}::fragment\n{}", name, preamble),
+    }
+}
+
+/// Inverse of the camera's combined view-projection matrix. In the compact
+/// G-buffer layout the light pass multiplies a fragment's clip-space position
+/// (rebuilt from the hardware depth) by this to recover the *world-space*
+/// position, matching the explicit-position path instead of stopping at view
+/// space. Falls back to the identity on the (degenerate) non-invertible case.
+fn inv_view_projection(camera: &Camera) -> [[f32; 4]; 4] {
+    (camera.projection * camera.view)
+        .try_inverse()
+        .unwrap_or_else(na::Matrix4::identity)
+        .into()
+}
+
+// Render-graph slot names. The `world_pos` slot is absent in the compact
+// layout, where position is reconstructed from depth instead.
+const SLOT_WORLD_POS: &str = "world_pos";
+const SLOT_WORLD_NORMAL: &str = "world_normal";
+const SLOT_ALBEDO_METALLIC: &str = "albedo_metallic";
+const SLOT_ROUGHNESS_AO: &str = "roughness_ao";
+const SLOT_LIGHT: &str = "light";
+
+/// Declare the deferred pipeline's passes as render-graph nodes for the given
+/// layout. The graph validates the slot wiring and owns the backing textures,
+/// so the G-buffer layout lives in one place instead of hardcoded arrays.
+fn deferred_nodes(compact: bool) -> Vec<Node> {
+    let normal_format = if compact {
+        SlotFormat::U16U16
+    } else {
+        SlotFormat::F32F32F32F32
+    };
+
+    let mut scene = Node::new("scene")
+        .writes(SLOT_WORLD_NORMAL, normal_format)
+        .writes(SLOT_ALBEDO_METALLIC, SlotFormat::U8U8U8U8)
+        .writes(SLOT_ROUGHNESS_AO, SlotFormat::U8U8U8U8);
+    let mut light = Node::new("light")
+        .reads(SLOT_WORLD_NORMAL)
+        .reads(SLOT_ALBEDO_METALLIC)
+        .reads(SLOT_ROUGHNESS_AO)
+        .writes(SLOT_LIGHT, SlotFormat::F32F32F32F32);
+
+    if !compact {
+        scene = scene.writes(SLOT_WORLD_POS, SlotFormat::F32F32F32F32);
+        light = light.reads(SLOT_WORLD_POS);
+    }
+
+    let composition = Node::new("composition")
+        .reads(SLOT_LIGHT)
+        .reads(SLOT_WORLD_NORMAL);
+
+    vec![scene, light, composition]
+}
 
 pub struct DeferredShading {
     config: Config,
 
-    scene_textures: [Texture2d; NUM_TEXTURES],
+    /// Owns and reallocates the scene/light slot textures declared by
+    /// [`deferred_nodes`], keyed by slot name, so resize is graph-driven.
+    graph: RenderGraph,
+
+    /// Hardware depth of the scene pass, allocated only in the compact layout,
+    /// where it backs the scene framebuffer's depth attachment and is sampled
+    /// to reconstruct world-space position.
+    depth_texture: Option<glium::texture::DepthTexture2d>,
+
     shadow_texture: Option<Texture2d>,
 
-    light_texture: Texture2d,
+    /// The per-frame `Camera`, uploaded once as a std140 block and bound to
+    /// both `light_pass` draws, instead of being rebuilt into a per-draw
+    /// `uniform!{}` tuple for every call.
+    context_block: UniformBlock<Camera>,
 
-    main_light_screen_quad_program: glium::Program,
-    light_object_program: glium::Program,
+    /// Built through a shared `ProgramCache`, so a `shadow_filter` ×
+    /// `compact_gbuffer` permutation already built for another
+    /// `DeferredShading` instance is reused instead of recompiled.
+    main_light_screen_quad_program: Rc<glium::Program>,
+    additional_light_program: Rc<glium::Program>,
 
     screen_quad: ScreenQuad,
-    sphere: Mesh<basic_obj::Vertex>,
 
-    light_instances: Vec<<Light as InstanceInput>::Vertex>,
-    light_instancing: Instancing<Light>,
+    /// Scratch buffer of additional-light GPU records, reused each frame to
+    /// avoid a per-frame allocation in the render path.
+    light_records: Vec<GpuLight>,
+
+    cluster_grid: ClusterGrid,
 }
 
 impl RenderPassComponent for DeferredShading {
     fn clear_buffers<F: glium::backend::Facade>(&self, facade: &F) -> Result<(), DrawError> {
-        let mut framebuffer = glium::framebuffer::MultiOutputFrameBuffer::new(
-            facade,
-            self.output_textures().iter().cloned(),
-        )?;
-        framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+        // The scene/light slots are cleared by the graph itself, in
+        // dependency order; the shadow map isn't a graph slot, so it's
+        // cleared alongside.
+        self.graph.clear_buffers(facade)?;
+
+        if let Some(shadow_texture) = self.shadow_texture.as_ref() {
+            let mut framebuffer = glium::framebuffer::SimpleFrameBuffer::new(facade, shadow_texture)?;
+            framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+        }
 
         Ok(())
     }
@@ -79,15 +439,26 @@ impl ScenePassComponent for DeferredShading {
         core: shader::Core<(Context, P), I, V>,
     ) -> shader::Core<(Context, P), I, V> {
         // Write scene to separate buffers
-        shaders::scene_buffers_core_transform(self.shadow_texture.is_some(), core)
+        shaders::scene_buffers_core_transform(
+            self.shadow_texture.is_some(),
+            self.config.compact_gbuffer,
+            core,
+        )
     }
 
     fn output_textures(&self) -> Vec<(&'static str, &Texture2d)> {
         let mut result = vec![
-            ("f_world_pos", &self.scene_textures[0]),
-            ("f_world_normal", &self.scene_textures[1]),
+            ("f_world_normal", self.slot(SLOT_WORLD_NORMAL)),
+            ("f_albedo_metallic", self.slot(SLOT_ALBEDO_METALLIC)),
+            ("f_roughness_ao", self.slot(SLOT_ROUGHNESS_AO)),
         ];
 
+        // The explicit world-position slot is only declared in the full layout;
+        // the compact layout reconstructs it from depth instead.
+        if let Some(world_pos_texture) = self.graph.slot(SLOT_WORLD_POS) {
+            result.insert(0, ("f_world_pos", world_pos_texture));
+        }
+
         if let Some(shadow_texture) = self.shadow_texture.as_ref() {
             result.push(("f_shadow", shadow_texture));
         }
@@ -95,6 +466,16 @@ impl ScenePassComponent for DeferredShading {
         result
     }
 
+    /// In the compact layout, the scene framebuffer binds this sampled
+    /// `DepthTexture2d` as its depth attachment instead of a scratch
+    /// renderbuffer, so the depth the scene pass writes for z-testing is the
+    /// same depth `light_pass` later samples to reconstruct world position.
+    /// In the full layout there's no texture to bind: depth testing still
+    /// happens, just into an attachment `light_pass` never reads back.
+    fn depth_texture(&self) -> Option<&glium::texture::DepthTexture2d> {
+        self.depth_texture.as_ref()
+    }
+
     fn params(&self, _: &Context) {}
 }
 
@@ -125,61 +506,96 @@ impl CompositionPassComponent for DeferredShading {
 
     fn params(&self) -> CompositionPassParams {
         CompositionPassParams {
-            light_texture: &self.light_texture,
-            normal_texture: &self.scene_textures[1],
+            light_texture: self.slot(SLOT_LIGHT),
+            normal_texture: self.slot(SLOT_WORLD_NORMAL),
         }
     }
 }
 
 impl DeferredShading {
+    /// `cache` is shared across every `DeferredShading` (and any other
+    /// deferred-shading instance drawing with the same flags), so a
+    /// shadow-filter × normal-encoding permutation already built
+    /// elsewhere is reused here instead of being recompiled.
     pub fn create<F: glium::backend::Facade>(
         facade: &F,
+        cache: &mut ProgramCache,
         config: &Config,
         have_shadows: bool,
         target_size: (u32, u32),
     ) -> Result<DeferredShading, CreationError> {
         info!("Creating deferred buffer textures");
-        let scene_textures = [
-            Self::create_texture(facade, target_size)?,
-            Self::create_texture(facade, target_size)?,
-        ];
+        // The node list is fixed by `deferred_nodes` and always structurally
+        // valid; only texture allocation (a `CreationError`) can fail here.
+        let graph = RenderGraph::build(facade, deferred_nodes(config.compact_gbuffer), target_size)
+            .map_err(|err| match err {
+                GraphError::Creation(err) => err,
+                other => unreachable!("deferred render graph is statically invalid: {}", other),
+            })?;
+        let depth_texture = if config.compact_gbuffer {
+            Some(Self::create_depth_texture(facade, target_size)?)
+        } else {
+            None
+        };
         let shadow_texture = if have_shadows {
             Some(Self::create_shadow_texture(facade, target_size)?)
         } else {
             None
         };
-        let light_texture = Self::create_texture(facade, target_size)?;
 
         info!("Creating deferred light programs");
-        let main_light_screen_quad_core = shaders::main_light_screen_quad_core(have_shadows);
-        let main_light_screen_quad_program =
-            main_light_screen_quad_core.build_program(facade, shader::InstancingMode::Uniforms)?;
-        let light_object_core = shaders::light_object_core();
-        let light_object_program =
-            light_object_core.build_program(facade, shader::InstancingMode::Vertex)?;
+        let shadow_filter = clamp_shadow_filter(&config.shadow_filter);
+        let main_light_key = permutation_key(
+            "main_light_screen_quad",
+            &main_light_flags(have_shadows, &shadow_filter, config.compact_gbuffer),
+        );
+        let main_light_screen_quad_program = cache.get_or_build(main_light_key, |_| {
+            shaders::main_light_screen_quad_core(have_shadows, &shadow_filter, config.compact_gbuffer)
+                .build_program(facade, shader::InstancingMode::Uniforms)
+        })?;
+
+        // The additional lights are accumulated in a single screen-space pass
+        // that reads each fragment's cluster light list, rather than one
+        // additive sphere per light.
+        let additional_light_key = permutation_key(
+            "cluster_light_screen_quad",
+            &additional_light_flags(config.compact_gbuffer),
+        );
+        let additional_light_program = cache.get_or_build(additional_light_key, |_| {
+            shaders::cluster_light_screen_quad_core(config.compact_gbuffer)
+                .build_program(facade, shader::InstancingMode::Uniforms)
+        })?;
+
+        // Seeded with an identity camera; `light_pass` re-uploads the real
+        // one every frame via `UniformBlock::update`.
+        let context_block = UniformBlock::create(
+            facade,
+            &Camera {
+                view: na::Matrix4::identity(),
+                projection: na::Matrix4::identity(),
+                viewport_size: target_size,
+            },
+        )?;
 
         info!("Creating screen quad");
         let screen_quad = ScreenQuad::create(facade)?;
 
-        info!("Creating sphere");
-        let sphere = BasicObj::Sphere.create_mesh(facade)?;
-
-        info!("Creating light buffers");
-        let light_instancing = Instancing::create(facade)?;
+        info!("Creating cluster grid");
+        let cluster_grid = ClusterGrid::create(facade, config.cluster_grid_size)?;
 
         info!("Deferred shading initialized");
 
         Ok(DeferredShading {
             config: config.clone(),
-            scene_textures,
+            graph,
+            depth_texture,
             shadow_texture,
-            light_texture,
+            context_block,
             main_light_screen_quad_program,
-            light_object_program,
+            additional_light_program,
             screen_quad,
-            sphere,
-            light_instances: Vec::new(),
-            light_instancing,
+            light_records: Vec::new(),
+            cluster_grid,
         })
     }
 
@@ -193,20 +609,31 @@ impl DeferredShading {
             target_size,
         );
 
-        self.scene_textures = [
-            Self::create_texture(facade, target_size)?,
-            Self::create_texture(facade, target_size)?,
-        ];
+        // Reallocation of the scene/light slot textures is driven by the graph,
+        // which reuses the same slot names so the pass wiring stays valid.
+        self.graph.on_target_resize(facade, target_size)?;
+
+        self.depth_texture = if self.config.compact_gbuffer {
+            Some(Self::create_depth_texture(facade, target_size)?)
+        } else {
+            None
+        };
 
         if let Some(shadow_texture) = self.shadow_texture.as_mut() {
             *shadow_texture = Self::create_shadow_texture(facade, target_size)?;
         }
 
-        self.light_texture = Self::create_texture(facade, target_size)?;
-
         Ok(())
     }
 
+    /// Fetch the texture backing a render-graph slot. The slot set is fixed by
+    /// [`deferred_nodes`], so a missing slot is a programming error.
+    fn slot(&self, name: &str) -> &Texture2d {
+        self.graph
+            .slot(name)
+            .unwrap_or_else(|| panic!("render-graph slot `{}` is missing", name))
+    }
+
     pub fn light_pass<F: glium::backend::Facade>(
         &mut self,
         facade: &F,
@@ -229,24 +656,66 @@ impl DeferredShading {
             ..Default::default()
         };
 
-        let mut light_buffer =
-            glium::framebuffer::SimpleFrameBuffer::new(facade, &self.light_texture)?;
+        let mut light_buffer = glium::framebuffer::SimpleFrameBuffer::new(
+            facade,
+            self.graph.slot(SLOT_LIGHT).expect("light slot is missing"),
+        )?;
 
         light_buffer.clear_color(0.0, 0.0, 0.0, 1.0);
 
+        // Both draws below are full-screen quads already in clip space, so
+        // unlike a mesh draw they must *not* be transformed by the live
+        // camera's view/projection: the baseline deliberately bound an
+        // identity `no_camera` `Context` to them for exactly this reason.
+        // Keep that identity here too -- only `viewport_size` needs to track
+        // the real target -- and upload it once per frame into the shared
+        // block instead of rebuilding a per-draw uniform tuple. In the
+        // compact layout the real camera reaches the shading math through
+        // the dedicated `inv_view_projection` uniform below; the full layout
+        // binds no camera-derived uniform to these draws.
+        self.context_block.update(&Camera {
+            view: na::Matrix4::identity(),
+            projection: na::Matrix4::identity(),
+            viewport_size: camera.viewport_size,
+        });
+        let context_uniforms = plain_uniforms! {
+            Context: self.context_block.buffer(),
+        };
+
+        // The full layout samples the explicit world-position target; the
+        // compact layout instead binds the hardware depth and the inverse
+        // view-projection needed to reconstruct world position from it.
+        let full_uniforms = self.graph.slot(SLOT_WORLD_POS).map(|world_pos_texture| {
+            plain_uniforms! {
+                position_texture: world_pos_texture,
+            }
+        });
+        let compact_uniforms = self.depth_texture.as_ref().map(|depth_texture| {
+            plain_uniforms! {
+                depth_texture: depth_texture,
+                inv_view_projection: inv_view_projection(camera),
+            }
+        });
+        let shadow_uniforms = self.shadow_texture.as_ref().map(|shadow_texture| {
+            plain_uniforms! {
+                shadow_texture: shadow_texture,
+                shadow_poisson_kernel: &SHADOW_POISSON_KERNEL[..],
+            }
+        });
+
         let textures = (
             &uniform! {
-                position_texture: &self.scene_textures[0],
-                normal_texture: &self.scene_textures[1],
+                normal_texture: self.graph.slot(SLOT_WORLD_NORMAL).unwrap(),
+                albedo_metallic_texture: self.graph.slot(SLOT_ALBEDO_METALLIC).unwrap(),
+                roughness_ao_texture: self.graph.slot(SLOT_ROUGHNESS_AO).unwrap(),
             },
-            &self.shadow_texture.as_ref().map(|shadow_texture| {
-                plain_uniforms! {
-                    shadow_texture: shadow_texture,
-                }
-            }),
+            (
+                &context_uniforms,
+                (&full_uniforms, (&compact_uniforms, &shadow_uniforms)),
+            ),
         );
 
-        self.light_instances.clear();
+        self.light_records.clear();
         for light in lights {
             if light.is_main {
                 continue;
@@ -259,28 +728,32 @@ impl DeferredShading {
                     * (light.attenuation.x - i_max * 1.0 / self.config.light_min_threshold);
             let radius = (-light.attenuation.y + radicand.sqrt()) / (2.0 * light.attenuation.z);
 
-            let light = Light {
-                radius,
-                ..light.clone()
-            };
-
-            self.light_instances.push(light.to_vertex());
+            self.light_records.push([
+                [light.position.x, light.position.y, light.position.z, radius],
+                [light.color.x, light.color.y, light.color.z, 0.0],
+                [
+                    light.attenuation.x,
+                    light.attenuation.y,
+                    light.attenuation.z,
+                    0.0,
+                ],
+            ]);
         }
 
-        self.light_instancing
-            .update(facade, &self.light_instances)?;
+        // Cull the additional lights into the cluster grid so the light shader
+        // only touches the lights that actually reach each fragment's cluster.
+        self.cluster_grid.recompute_aabbs(camera);
+        self.cluster_grid.cull(facade, &self.light_records);
 
         // Draw main light
         for light in lights.iter() {
             if light.is_main {
-                // Fragment shader uses viewport size, but we don't need view/projection
-                let no_camera = Camera {
-                    view: na::Matrix4::identity(),
-                    projection: na::Matrix4::identity(),
-                    viewport_size: camera.viewport_size,
+                // The slope-scaled depth bias is exposed per light, so
+                // grazing-angle acne can be tuned independently for each.
+                let shadow_uniforms = plain_uniforms! {
+                    shadow_depth_bias: light.shadow_depth_bias,
                 };
-
-                let uniforms = (&textures, (no_camera, &light));
+                let uniforms = (&textures, (&light, shadow_uniforms));
 
                 light_buffer.draw(
                     &self.screen_quad.vertex_buffer,
@@ -292,52 +765,53 @@ impl DeferredShading {
             }
         }
 
-        // Draw additional light using instancing
-        let uniforms = (&textures, &camera);
-
-        // With backface culling, there is a problem in that lights are
-        // not rendered when the camera moves within the sphere. With
-        // frontface culling this problem does not happen.
-        // (I think there's some other downside, but I'm not sure what
-        // it is exactly.)
-        let draw_params = glium::DrawParameters {
-            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullCounterClockwise,
-            ..draw_params.clone()
-        };
-
-        self.light_instancing.as_drawable(&self.sphere).draw(
-            &self.light_object_program,
-            &uniforms,
+        // Accumulate the additional lights in a single screen-space pass. The
+        // per-cluster light lists produced by the compute cull are bound so the
+        // fragment shader loops over only the lights reaching its cluster,
+        // instead of drawing one additive sphere per light.
+        let uniforms = (&textures, self.cluster_grid.cluster_uniforms());
+
+        light_buffer.draw(
+            &self.screen_quad.vertex_buffer,
+            &self.screen_quad.index_buffer,
+            &self.additional_light_program,
+            &uniforms.to_uniforms(),
             &draw_params,
-            &mut light_buffer,
         )?;
 
         Ok(())
     }
 
-    fn create_texture<F: glium::backend::Facade>(
+    fn create_shadow_texture<F: glium::backend::Facade>(
         facade: &F,
         size: (u32, u32),
     ) -> Result<Texture2d, CreationError> {
         Ok(Texture2d::empty_with_format(
             facade,
-            glium::texture::UncompressedFloatFormat::F32F32F32F32,
+            glium::texture::UncompressedFloatFormat::F32,
             glium::texture::MipmapsOption::NoMipmap,
             size.0,
             size.1,
         )?)
     }
 
-    fn create_shadow_texture<F: glium::backend::Facade>(
+    fn create_depth_texture<F: glium::backend::Facade>(
         facade: &F,
         size: (u32, u32),
-    ) -> Result<Texture2d, CreationError> {
-        Ok(Texture2d::empty_with_format(
+    ) -> Result<glium::texture::DepthTexture2d, CreationError> {
+        Ok(glium::texture::DepthTexture2d::empty_with_format(
             facade,
-            glium::texture::UncompressedFloatFormat::F32,
+            glium::texture::DepthFormat::F32,
             glium::texture::MipmapsOption::NoMipmap,
             size.0,
             size.1,
         )?)
     }
+
+    /// The scene pass's hardware depth attachment, present only in the compact
+    /// layout. The geometry pass renders depth into this alongside the colour
+    /// targets so `light_pass` can reconstruct world-space position from it.
+    pub fn depth_attachment(&self) -> Option<&glium::texture::DepthTexture2d> {
+        self.depth_texture.as_ref()
+    }
 }
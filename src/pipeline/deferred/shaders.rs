@@ -0,0 +1,578 @@
+//! GLSL sources and shader-core transforms for the deferred pipeline.
+//!
+//! Fragment sources are assembled from a small [`ShaderRegistry`] of GLSL
+//! chunks through [`preprocess`], so the same [`FeatureFlags`] that key the
+//! `ProgramCache` (see `deferred::main_light_flags`) are also what selects
+//! the GLSL that actually gets compiled.
+
+use nalgebra as na;
+
+use crate::shader::preprocessor::{preprocess, FeatureFlags, ShaderRegistry};
+use crate::shader::Core;
+use crate::{screen_quad, Camera, Context, Light};
+
+use super::ShadowFilter;
+
+/// The chunks every deferred fragment shader may `#include`.
+fn registry() -> ShaderRegistry {
+    let mut registry = ShaderRegistry::new();
+    registry.insert("shadow.glsl", SHADOW_CHUNK);
+    registry.insert("pbr.glsl", PBR_CHUNK);
+    registry.insert("octahedral.glsl", OCTAHEDRAL_CHUNK);
+    registry
+}
+
+/// Resolve `source` under `flags`. The registry and every call site's source
+/// are fixed at compile time, so the only way this can fail is a typo in one
+/// of the chunks below.
+fn preprocess_fixed(source: &str, flags: &FeatureFlags) -> String {
+    preprocess(&registry(), source, flags)
+        .unwrap_or_else(|err| unreachable!("deferred shader source is statically valid: {}", err))
+}
+
+/// Percentage-closer and percentage-closer-soft shadow filtering, sampled
+/// over a rotated Poisson-disc kernel.
+///
+/// `sample_shadow` dispatches on the same `SHADOW_FILTER_PCF_TAPS` /
+/// `SHADOW_FILTER_PCSS_BLOCKER_TAPS` defines used to key the `ProgramCache`,
+/// so only the selected filter's taps end up in the compiled program.
+const SHADOW_CHUNK: &str = r#"
+uniform sampler2D shadow_texture;
+uniform vec2 shadow_poisson_kernel[16];
+
+float shadow_random_angle(vec2 seed) {
+    return 6.283185307 * fract(sin(dot(seed, vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+float shadow_hard_tap(vec3 shadow_coord, float bias) {
+    float occluder_depth = texture(shadow_texture, shadow_coord.xy).r;
+    return shadow_coord.z - bias > occluder_depth ? 0.0 : 1.0;
+}
+
+float shadow_pcf(vec3 shadow_coord, float bias, float radius, int taps) {
+    float angle = shadow_random_angle(shadow_coord.xy);
+    float s = sin(angle);
+    float c = cos(angle);
+
+    float sum = 0.0;
+    for (int i = 0; i < taps; ++i) {
+        vec2 offset = shadow_poisson_kernel[i];
+        vec2 rotated = vec2(offset.x * c - offset.y * s, offset.x * s + offset.y * c);
+        float occluder_depth = texture(shadow_texture, shadow_coord.xy + rotated * radius).r;
+        sum += shadow_coord.z - bias > occluder_depth ? 0.0 : 1.0;
+    }
+    return sum / float(taps);
+}
+
+#ifdef SHADOW_FILTER_PCSS_BLOCKER_TAPS
+float shadow_pcss(vec3 shadow_coord, float bias) {
+    const int blocker_taps = SHADOW_FILTER_PCSS_BLOCKER_TAPS;
+    const float light_size = SHADOW_FILTER_PCSS_LIGHT_SIZE;
+
+    float angle = shadow_random_angle(shadow_coord.xy);
+    float s = sin(angle);
+    float c = cos(angle);
+
+    // Blocker search: average the depth of every tap nearer the light than
+    // the receiver, to estimate how far the occluders sit from it.
+    float search_radius = light_size * shadow_coord.z;
+    float blocker_sum = 0.0;
+    float blocker_count = 0.0;
+
+    for (int i = 0; i < blocker_taps; ++i) {
+        vec2 offset = shadow_poisson_kernel[i];
+        vec2 rotated = vec2(offset.x * c - offset.y * s, offset.x * s + offset.y * c);
+        float occluder_depth = texture(shadow_texture, shadow_coord.xy + rotated * search_radius).r;
+        if (occluder_depth < shadow_coord.z - bias) {
+            blocker_sum += occluder_depth;
+            blocker_count += 1.0;
+        }
+    }
+
+    if (blocker_count < 1.0) {
+        return 1.0;
+    }
+
+    // Penumbra width grows with the light size and the blocker-to-receiver
+    // distance, and shrinks as the blocker approaches the receiver.
+    float avg_blocker_depth = blocker_sum / blocker_count;
+    float penumbra_width = (shadow_coord.z - avg_blocker_depth) * light_size / avg_blocker_depth;
+
+    return shadow_pcf(shadow_coord, bias, max(penumbra_width, 0.001), blocker_taps);
+}
+#endif
+
+float sample_shadow(vec3 shadow_coord, float bias) {
+#if defined(SHADOW_FILTER_PCSS_BLOCKER_TAPS)
+    return shadow_pcss(shadow_coord, bias);
+#elif defined(SHADOW_FILTER_PCF_TAPS)
+    return shadow_pcf(shadow_coord, bias, 0.0015, SHADOW_FILTER_PCF_TAPS);
+#else
+    return shadow_hard_tap(shadow_coord, bias);
+#endif
+}
+"#;
+
+/// Cook-Torrance microfacet BRDF: GGX normal distribution, Smith
+/// height-correlated geometry term, Schlick Fresnel, energy-conserved
+/// against a Lambertian diffuse term scaled by `(1 - metallic)`.
+const PBR_CHUNK: &str = r#"
+const float PI = 3.14159265359;
+
+float pbr_distribution_ggx(vec3 n, vec3 h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float n_dot_h = max(dot(n, h), 0.0);
+    float denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+float pbr_geometry_smith(vec3 n, vec3 v, vec3 l, float roughness) {
+    float n_dot_v = max(dot(n, v), 0.0);
+    float n_dot_l = max(dot(n, l), 0.0);
+    float a = roughness * roughness;
+    float a2 = a * a;
+    // Height-correlated: view and light masking share one denominator
+    // instead of being multiplied as independent terms.
+    float ggx_v = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - a2) + a2);
+    float ggx_l = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - a2) + a2);
+    return 2.0 * n_dot_v * n_dot_l / max(ggx_v + ggx_l, 0.0001);
+}
+
+vec3 pbr_fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// `radiance` is the light's incoming radiance at the surface (color already
+// scaled by attenuation), not yet multiplied by `n_dot_l`.
+vec3 cook_torrance(
+    vec3 n,
+    vec3 v,
+    vec3 l,
+    vec3 radiance,
+    vec3 albedo,
+    float metallic,
+    float roughness
+) {
+    vec3 h = normalize(v + l);
+    vec3 f0 = mix(vec3(0.04), albedo, metallic);
+
+    float ndf = pbr_distribution_ggx(n, h, roughness);
+    float g = pbr_geometry_smith(n, v, l, roughness);
+    vec3 f = pbr_fresnel_schlick(max(dot(h, v), 0.0), f0);
+
+    vec3 numerator = ndf * g * f;
+    float denominator = 4.0 * max(dot(n, v), 0.0) * max(dot(n, l), 0.0) + 0.0001;
+    vec3 specular = numerator / denominator;
+
+    // `f` is already the fraction reflected specularly, so the remainder
+    // `(1 - f)` goes to diffuse; metals have no diffuse term at all.
+    vec3 kd = (vec3(1.0) - f) * (1.0 - metallic);
+
+    float n_dot_l = max(dot(n, l), 0.0);
+    return (kd * albedo / PI + specular) * radiance * n_dot_l;
+}
+"#;
+
+/// Octahedral normal encode/decode: project the unit sphere onto the octahedron
+/// `|x| + |y| + |z| = 1`, fold the lower hemisphere's corners into the unit
+/// square, and remap `[-1, 1]` to `[0, 1]` for storage in an unorm target.
+const OCTAHEDRAL_CHUNK: &str = r#"
+vec2 encode_octahedral(vec3 n) {
+    n /= abs(n.x) + abs(n.y) + abs(n.z);
+    vec2 folded = n.z >= 0.0 ? n.xy : (vec2(1.0) - abs(n.yx)) * sign(n.xy);
+    return folded * 0.5 + 0.5;
+}
+
+vec3 decode_octahedral(vec2 encoded) {
+    vec2 oct = encoded * 2.0 - vec2(1.0);
+    vec3 n = vec3(oct, 1.0 - abs(oct.x) - abs(oct.y));
+    float t = max(-n.z, 0.0);
+    n.xy += n.xy >= vec2(0.0) ? vec2(-t) : vec2(t);
+    return normalize(n);
+}
+"#;
+
+/// Compute shader dispatched once per cluster (`local_size` is `1x1x1`, so
+/// `gl_GlobalInvocationID` maps directly onto cluster coordinates). Tests
+/// every light's bounding sphere against the dispatched cluster's AABB and
+/// appends the indices of the lights that intersect it into a shared,
+/// per-cluster index list.
+///
+/// `MAX_LIGHTS_PER_CLUSTER` below must agree with `deferred::MAX_LIGHTS_PER_CLUSTER`.
+pub fn cluster_cull_compute() -> &'static str {
+    r#"
+#version 430
+
+layout(local_size_x = 1, local_size_y = 1, local_size_z = 1) in;
+
+struct ClusterLight {
+    vec4 position_radius;
+    vec4 color;
+    vec4 attenuation;
+};
+
+layout(std430) buffer Lights {
+    ClusterLight lights[];
+};
+
+layout(std430) buffer ClusterAabbs {
+    vec4 cluster_aabbs[];
+};
+
+layout(std430) buffer LightIndices {
+    uint light_indices[];
+};
+
+layout(std430) buffer LightGrid {
+    uvec2 light_grid[];
+};
+
+uniform uint num_lights;
+uniform uint num_clusters;
+uniform uvec3 grid_size;
+
+const uint MAX_LIGHTS_PER_CLUSTER = 256u;
+
+void main() {
+    uint cluster_index = gl_GlobalInvocationID.x
+        + gl_GlobalInvocationID.y * grid_size.x
+        + gl_GlobalInvocationID.z * grid_size.x * grid_size.y;
+
+    if (cluster_index >= num_clusters) {
+        return;
+    }
+
+    vec3 aabb_min = cluster_aabbs[cluster_index * 2u].xyz;
+    vec3 aabb_max = cluster_aabbs[cluster_index * 2u + 1u].xyz;
+
+    uint offset = cluster_index * MAX_LIGHTS_PER_CLUSTER;
+    uint count = 0u;
+
+    for (uint i = 0u; i < num_lights && count < MAX_LIGHTS_PER_CLUSTER; ++i) {
+        vec3 center = lights[i].position_radius.xyz;
+        float radius = lights[i].position_radius.w;
+
+        // Closest point on the cluster AABB to the light's center; the light
+        // reaches the cluster if that point is within `radius` of it.
+        vec3 closest = clamp(center, aabb_min, aabb_max);
+        float dist_sq = dot(closest - center, closest - center);
+
+        if (dist_sq <= radius * radius) {
+            light_indices[offset + count] = i;
+            count += 1u;
+        }
+    }
+
+    light_grid[cluster_index] = uvec2(offset, count);
+}
+"#
+}
+
+/// Recompute the world-space AABB of every cluster in a `size.0 * size.1 *
+/// size.2` grid with exponential depth slices, and write two `vec4`s (min,
+/// max) per cluster into `buffer`.
+///
+/// Depth slices are distributed as `near * (far / near)^(k / num_slices)`,
+/// which keeps clusters roughly screen-uniform in size despite perspective
+/// making distant geometry cover fewer screen pixels per world unit.
+pub fn fill_cluster_aabbs(
+    size: (u32, u32, u32),
+    camera: &Camera,
+    buffer: &mut glium::uniforms::UniformBuffer<[[f32; 4]]>,
+) {
+    let (nx, ny, nz) = size;
+
+    // For a standard OpenGL perspective matrix, `projection[(2, 2)]` and
+    // `projection[(2, 3)]` are `(far + near) / (near - far)` and
+    // `2 * far * near / (near - far)`; solving that pair recovers near/far
+    // without threading them through separately.
+    let a = camera.projection[(2, 2)];
+    let b = camera.projection[(2, 3)];
+    let near = b / (a - 1.0);
+    let far = b / (a + 1.0);
+
+    let proj_x = camera.projection[(0, 0)];
+    let proj_y = camera.projection[(1, 1)];
+    let inv_view = camera
+        .view
+        .try_inverse()
+        .unwrap_or_else(na::Matrix4::identity);
+
+    // Unproject a screen-space tile corner (`ndc` in `[-1, 1]^2`) at view-space
+    // depth `depth` (the positive distance along the camera's forward axis)
+    // into world space.
+    let unproject = |ndc: (f32, f32), depth: f32| -> na::Point3<f32> {
+        let view_pos = na::Vector4::new(ndc.0 * depth / proj_x, ndc.1 * depth / proj_y, -depth, 1.0);
+        let world_pos = inv_view * view_pos;
+        na::Point3::new(world_pos.x, world_pos.y, world_pos.z)
+    };
+
+    let mut aabbs = Vec::with_capacity(2 * (nx * ny * nz) as usize);
+
+    for k in 0..nz {
+        let z_near = near * (far / near).powf(k as f32 / nz as f32);
+        let z_far = near * (far / near).powf((k + 1) as f32 / nz as f32);
+
+        for j in 0..ny {
+            let ndc_y0 = 1.0 - 2.0 * j as f32 / ny as f32;
+            let ndc_y1 = 1.0 - 2.0 * (j + 1) as f32 / ny as f32;
+
+            for i in 0..nx {
+                let ndc_x0 = 2.0 * i as f32 / nx as f32 - 1.0;
+                let ndc_x1 = 2.0 * (i + 1) as f32 / nx as f32 - 1.0;
+
+                let corners = [
+                    unproject((ndc_x0, ndc_y0), z_near),
+                    unproject((ndc_x1, ndc_y0), z_near),
+                    unproject((ndc_x0, ndc_y1), z_near),
+                    unproject((ndc_x1, ndc_y1), z_near),
+                    unproject((ndc_x0, ndc_y0), z_far),
+                    unproject((ndc_x1, ndc_y0), z_far),
+                    unproject((ndc_x0, ndc_y1), z_far),
+                    unproject((ndc_x1, ndc_y1), z_far),
+                ];
+
+                let mut min = corners[0];
+                let mut max = corners[0];
+                for corner in &corners[1..] {
+                    min.x = min.x.min(corner.x);
+                    min.y = min.y.min(corner.y);
+                    min.z = min.z.min(corner.z);
+                    max.x = max.x.max(corner.x);
+                    max.y = max.y.max(corner.y);
+                    max.z = max.z.max(corner.z);
+                }
+
+                aabbs.push([min.x, min.y, min.z, 0.0]);
+                aabbs.push([max.x, max.y, max.z, 0.0]);
+            }
+        }
+    }
+
+    // Safe to unwrap: `aabbs` has exactly the `2 * nx * ny * nz` elements the
+    // buffer was sized for in `ClusterGrid::create`.
+    buffer.slice_mut(..aabbs.len()).unwrap().write(&aabbs);
+}
+
+/// Per-draw material parameters feeding `write_gbuffer`'s BRDF inputs.
+///
+/// Bound through the same `impl_uniform_input!` convention as `Light` and
+/// `CompositionPassParams`, so a scene `Drawable` surfaces its material by
+/// folding `Material` into its own per-draw params (the `P` in
+/// `scene_buffers_core_transform`'s `Core<(Context, P), I, V>`) instead of
+/// `write_gbuffer` reading values nothing ever provides.
+#[derive(Copy, Clone, Debug)]
+pub struct Material {
+    pub albedo: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub ao: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            albedo: [1.0, 1.0, 1.0],
+            metallic: 0.0,
+            roughness: 1.0,
+            ao: 1.0,
+        }
+    }
+}
+
+impl_uniform_input!(
+    Material,
+    self => {
+        material_albedo: [f32; 3] = self.albedo,
+        material_metallic: f32 = self.metallic,
+        material_roughness: f32 = self.roughness,
+        material_ao: f32 = self.ao,
+    },
+);
+
+/// Transform the scene pass's core to additionally write the G-buffer
+/// outputs (`f_world_normal`, `f_albedo_metallic`, `f_roughness_ao`, and
+/// `f_world_pos` in the full layout only) instead of just the forward-shaded
+/// color.
+pub fn scene_buffers_core_transform<P, I, V>(
+    have_shadows: bool,
+    compact_gbuffer: bool,
+    core: Core<(Context, P), I, V>,
+) -> Core<(Context, P), I, V> {
+    let _ = have_shadows;
+
+    let mut flags = FeatureFlags::new();
+    if compact_gbuffer {
+        flags = flags.enable("COMPACT_GBUFFER");
+    }
+
+    let source = preprocess_fixed(
+        r#"
+#include "octahedral.glsl"
+
+uniform vec3 material_albedo;
+uniform float material_metallic;
+uniform float material_roughness;
+uniform float material_ao;
+
+void write_gbuffer(vec3 world_pos, vec3 world_normal) {
+#ifdef COMPACT_GBUFFER
+    f_world_normal = encode_octahedral(world_normal);
+#else
+    f_world_pos = vec4(world_pos, 1.0);
+    f_world_normal = world_normal;
+#endif
+    f_albedo_metallic = vec4(material_albedo, material_metallic);
+    f_roughness_ao = vec4(material_roughness, material_ao, 0.0, 0.0);
+}
+"#,
+        &flags,
+    );
+
+    core.with_extra_fragment_source(&source)
+}
+
+/// Transform the composition pass's core to resolve the lit scene by
+/// tonemapping the light accumulation buffer.
+pub fn composition_core_transform(
+    core: Core<Context, (), screen_quad::Vertex>,
+) -> Core<Context, (), screen_quad::Vertex> {
+    core.with_extra_fragment_source(
+        r#"
+vec3 resolve_composition(vec3 light) {
+    // Simple Reinhard tonemap; the light buffer holds unbounded HDR radiance.
+    return light / (light + vec3(1.0));
+}
+"#,
+    )
+}
+
+/// The main (shadow-casting) light's screen-space pass: a full-screen quad
+/// drawn once, shading every fragment from the G-buffer against the single
+/// main light, filtered through `sample_shadow` when shadows are enabled.
+pub fn main_light_screen_quad_core(
+    have_shadows: bool,
+    shadow_filter: &ShadowFilter,
+    compact_gbuffer: bool,
+) -> Core<(Context, Light), (), screen_quad::Vertex> {
+    // Reuse the exact flags the cache keys this permutation by, so the
+    // compiled shader and the cache slot it's stored under can never drift.
+    let flags = super::main_light_flags(have_shadows, shadow_filter, compact_gbuffer);
+
+    let source = preprocess_fixed(
+        r#"
+#include "shadow.glsl"
+#include "octahedral.glsl"
+#include "pbr.glsl"
+
+#ifdef COMPACT_GBUFFER
+vec3 shade_main_light(
+    vec3 world_pos,
+    vec2 encoded_normal,
+    vec3 albedo,
+    float metallic,
+    float roughness,
+    vec3 light_dir,
+    vec3 light_color,
+    vec3 view_dir,
+    vec4 shadow_coord,
+    float shadow_depth_bias
+) {
+    vec3 normal = decode_octahedral(encoded_normal);
+#else
+vec3 shade_main_light(
+    vec3 world_pos,
+    vec3 normal,
+    vec3 albedo,
+    float metallic,
+    float roughness,
+    vec3 light_dir,
+    vec3 light_color,
+    vec3 view_dir,
+    vec4 shadow_coord,
+    float shadow_depth_bias
+) {
+#endif
+
+    float visibility = 1.0;
+#ifdef HAVE_SHADOWS
+    // Slope-scaled: grazing-angle fragments get a larger bias so they don't
+    // self-shadow ("shadow acne"), without over-biasing near-perpendicular ones.
+    float slope_scale = clamp(1.0 - dot(normal, light_dir), 0.0, 1.0);
+    float bias = shadow_depth_bias * slope_scale;
+    visibility = sample_shadow(shadow_coord.xyz / shadow_coord.w, bias);
+#endif
+
+    return cook_torrance(normal, view_dir, light_dir, light_color, albedo, metallic, roughness) * visibility;
+}
+"#,
+        &flags,
+    );
+
+    Core::empty().with_extra_fragment_source(&source)
+}
+
+/// The additional (non-shadow-casting) lights' screen-space pass: a
+/// full-screen quad drawn once, shading every fragment against only the
+/// lights in its cluster's index list (bound by `ClusterGrid::cluster_uniforms`)
+/// instead of one additive draw per light.
+pub fn cluster_light_screen_quad_core(compact_gbuffer: bool) -> Core<Context, (), screen_quad::Vertex> {
+    let flags = super::additional_light_flags(compact_gbuffer);
+
+    let source = preprocess_fixed(
+        r#"
+#include "octahedral.glsl"
+#include "pbr.glsl"
+
+uniform uvec3 grid_size;
+
+#ifdef COMPACT_GBUFFER
+vec3 shade_cluster_lights(
+    vec3 world_pos,
+    vec2 encoded_normal,
+    vec3 albedo,
+    float metallic,
+    float roughness,
+    vec3 view_dir,
+    uint cluster_index
+) {
+    vec3 normal = decode_octahedral(encoded_normal);
+#else
+vec3 shade_cluster_lights(
+    vec3 world_pos,
+    vec3 normal,
+    vec3 albedo,
+    float metallic,
+    float roughness,
+    vec3 view_dir,
+    uint cluster_index
+) {
+#endif
+    uvec2 range = light_grid[cluster_index];
+    vec3 result = vec3(0.0);
+
+    for (uint i = 0u; i < range.y; ++i) {
+        uint light_index = light_indices[range.x + i];
+
+        vec3 light_pos = lights[light_index].position_radius.xyz;
+        vec3 light_vec = light_pos - world_pos;
+        float dist = length(light_vec);
+        vec3 light_dir = light_vec / max(dist, 0.0001);
+
+        vec3 attenuation_coeffs = lights[light_index].attenuation.xyz;
+        float attenuation = 1.0
+            / (attenuation_coeffs.x + attenuation_coeffs.y * dist + attenuation_coeffs.z * dist * dist);
+        vec3 radiance = lights[light_index].color.rgb * attenuation;
+
+        result += cook_torrance(normal, view_dir, light_dir, radiance, albedo, metallic, roughness);
+    }
+
+    return result;
+}
+"#,
+        &flags,
+    );
+
+    Core::empty().with_extra_fragment_source(&source)
+}
@@ -0,0 +1,254 @@
+//! A declarative render-graph layer over the `RenderPassComponent` traits.
+//!
+//! The hand-wired pipeline in `pipeline` threads textures between passes by
+//! convention: a `ScenePassComponent`'s `output_textures` have to line up with
+//! the sampler names the next pass expects, and every attachment has to be
+//! reallocated by hand in `on_target_resize`. This module lets each pass
+//! instead *declare* its named input and output slots, validates that every
+//! input is produced before it is consumed, and owns the `Texture2d`
+//! allocation (and reuse) for the declared slots.
+
+use std::collections::HashMap;
+
+use glium::Texture2d;
+
+use crate::DrawError;
+
+pub use crate::CreationError;
+
+/// The pixel format of a graph resource slot.
+///
+/// These mirror the `glium` uncompressed formats actually used by the deferred
+/// pipeline; the graph only needs enough to allocate the backing texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotFormat {
+    /// Four 32-bit float channels, e.g. world position / normal.
+    F32F32F32F32,
+
+    /// Four 8-bit unorm channels, e.g. albedo + metallic.
+    U8U8U8U8,
+
+    /// Two 16-bit channels, e.g. octahedral-encoded normals.
+    U16U16,
+
+    /// A single 32-bit float channel, e.g. the shadow map.
+    F32,
+}
+
+impl SlotFormat {
+    fn to_glium(self) -> glium::texture::UncompressedFloatFormat {
+        use glium::texture::UncompressedFloatFormat as F;
+
+        match self {
+            SlotFormat::F32F32F32F32 => F::F32F32F32F32,
+            SlotFormat::U8U8U8U8 => F::U8U8U8U8,
+            SlotFormat::U16U16 => F::U16U16,
+            SlotFormat::F32 => F::F32,
+        }
+    }
+}
+
+/// A named resource slot together with its format.
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub name: &'static str,
+    pub format: SlotFormat,
+}
+
+/// A node in the render graph: a single pass that consumes some slots and
+/// produces others.
+pub struct Node {
+    pub name: &'static str,
+    pub inputs: Vec<&'static str>,
+    pub outputs: Vec<Slot>,
+}
+
+impl Node {
+    pub fn new(name: &'static str) -> Self {
+        Node {
+            name,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Declare that this node reads the slot `name`, which must be produced by
+    /// an earlier node.
+    pub fn reads(mut self, name: &'static str) -> Self {
+        self.inputs.push(name);
+        self
+    }
+
+    /// Declare that this node writes the slot `name` with the given format.
+    pub fn writes(mut self, name: &'static str, format: SlotFormat) -> Self {
+        self.outputs.push(Slot { name, format });
+        self
+    }
+}
+
+/// An error in the declared structure of a render graph, or in allocating the
+/// textures backing it.
+#[derive(Debug)]
+pub enum GraphError {
+    /// A node reads a slot that no earlier node produces.
+    MissingInput {
+        node: &'static str,
+        slot: &'static str,
+    },
+
+    /// Two nodes declare the same output slot.
+    DuplicateOutput { slot: &'static str },
+
+    /// Allocating a slot's backing texture failed, e.g. because the target
+    /// was resized to a degenerate size.
+    Creation(CreationError),
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GraphError::MissingInput { node, slot } => write!(
+                f,
+                "render-graph node `{}` reads slot `{}`, which is never produced",
+                node, slot,
+            ),
+            GraphError::DuplicateOutput { slot } => {
+                write!(f, "render-graph slot `{}` is produced by two nodes", slot)
+            }
+            GraphError::Creation(err) => {
+                write!(f, "failed to allocate render-graph slot texture: {}", err)
+            }
+        }
+    }
+}
+
+impl From<CreationError> for GraphError {
+    fn from(err: CreationError) -> Self {
+        GraphError::Creation(err)
+    }
+}
+
+impl std::error::Error for GraphError {}
+
+/// A validated render graph: an ordered sequence of passes plus the textures
+/// backing their declared slots.
+pub struct RenderGraph {
+    nodes: Vec<Node>,
+    textures: HashMap<&'static str, Texture2d>,
+}
+
+impl RenderGraph {
+    /// Validate the given nodes (in execution order) and allocate a texture
+    /// for every declared output slot.
+    ///
+    /// Validation fails if any node reads a slot that no earlier node writes,
+    /// or if two nodes produce the same slot. Allocation can also fail, e.g.
+    /// if the target size is degenerate.
+    pub fn build<F: glium::backend::Facade>(
+        facade: &F,
+        nodes: Vec<Node>,
+        target_size: (u32, u32),
+    ) -> Result<RenderGraph, GraphError> {
+        let mut produced: HashMap<&'static str, SlotFormat> = HashMap::new();
+
+        for node in &nodes {
+            for input in &node.inputs {
+                if !produced.contains_key(input) {
+                    return Err(GraphError::MissingInput {
+                        node: node.name,
+                        slot: input,
+                    });
+                }
+            }
+
+            for slot in &node.outputs {
+                if produced.insert(slot.name, slot.format).is_some() {
+                    return Err(GraphError::DuplicateOutput { slot: slot.name });
+                }
+            }
+        }
+
+        let mut graph = RenderGraph {
+            nodes,
+            textures: HashMap::new(),
+        };
+        graph.allocate(facade, target_size, &produced)?;
+
+        Ok(graph)
+    }
+
+    /// Look up the texture backing a slot, if the slot is produced by the graph.
+    pub fn slot(&self, name: &str) -> Option<&Texture2d> {
+        self.textures.get(name)
+    }
+
+    /// Reallocate every slot texture for a new target size, reusing slot names
+    /// so existing wiring stays valid. Slots whose size hasn't actually
+    /// changed keep their existing texture instead of being recreated.
+    pub fn on_target_resize<F: glium::backend::Facade>(
+        &mut self,
+        facade: &F,
+        target_size: (u32, u32),
+    ) -> Result<(), CreationError> {
+        let formats: HashMap<&'static str, SlotFormat> = self
+            .nodes
+            .iter()
+            .flat_map(|node| node.outputs.iter())
+            .map(|slot| (slot.name, slot.format))
+            .collect();
+
+        self.allocate(facade, target_size, &formats)
+    }
+
+    /// Clear every slot texture in dependency (node) order.
+    pub fn clear_buffers<F: glium::backend::Facade>(&self, facade: &F) -> Result<(), DrawError> {
+        for node in &self.nodes {
+            for slot in &node.outputs {
+                if let Some(texture) = self.textures.get(slot.name) {
+                    let mut framebuffer =
+                        glium::framebuffer::SimpleFrameBuffer::new(facade, texture)?;
+                    framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Allocate a texture for every slot in `formats`, sized to `target_size`.
+    /// A slot already holding a texture of that exact size is left in place
+    /// instead of being recreated (its format is fixed for the graph's
+    /// lifetime, so size is the only thing that can have changed).
+    fn allocate<F: glium::backend::Facade>(
+        &mut self,
+        facade: &F,
+        target_size: (u32, u32),
+        formats: &HashMap<&'static str, SlotFormat>,
+    ) -> Result<(), CreationError> {
+        let mut next = HashMap::with_capacity(formats.len());
+
+        for (&name, &format) in formats {
+            let reused = self
+                .textures
+                .remove(name)
+                .filter(|texture| texture.width() == target_size.0 && texture.height() == target_size.1);
+
+            let texture = match reused {
+                Some(texture) => texture,
+                None => Texture2d::empty_with_format(
+                    facade,
+                    format.to_glium(),
+                    glium::texture::MipmapsOption::NoMipmap,
+                    target_size.0,
+                    target_size.1,
+                )?,
+            };
+
+            next.insert(name, texture);
+        }
+
+        self.textures = next;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! std140 uniform-buffer backing for shared shader inputs.
+//!
+//! Every draw in `deferred::DeferredShading::light_pass` rebuilds a
+//! `uniform!{}` / `to_uniforms()` tuple and uploads the camera and context
+//! scalars individually, which is wasteful when many lights and instances
+//! share the same data. This module lays those inputs out into a correctly
+//! padded std140 block so they can be uploaded once into a
+//! `glium::uniforms::UniformBuffer` and bound as a block.
+
+use glium::uniforms::UniformBuffer;
+
+use crate::{Camera, CreationError};
+
+/// A value that can be written into a std140-laid-out uniform block.
+///
+/// Implementors are responsible for matching the std140 rules: `vec3`s are
+/// aligned to 16 bytes, a `mat4` is four column `vec4`s, and array elements are
+/// stride-padded to 16 bytes.
+pub trait Std140: Copy {
+    /// The plain-old-data block actually uploaded to the GPU.
+    type Block: Copy + Send + 'static;
+
+    /// Lay `self` out into its padded std140 block.
+    fn std140(&self) -> Self::Block;
+}
+
+/// std140 layout of the per-frame `Context`/`Camera` data shared by every light
+/// draw. Field order and padding follow the std140 rules so it matches a
+/// `layout(std140)` block declared in GLSL.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ContextBlock {
+    pub view: [[f32; 4]; 4],
+    pub projection: [[f32; 4]; 4],
+    pub viewport_size: [f32; 2],
+    // Pad the trailing `vec2` out to a 16-byte boundary.
+    _pad: [f32; 2],
+}
+
+impl Std140 for Camera {
+    type Block = ContextBlock;
+
+    fn std140(&self) -> ContextBlock {
+        ContextBlock {
+            view: self.view.into(),
+            projection: self.projection.into(),
+            viewport_size: [self.viewport_size.0 as f32, self.viewport_size.1 as f32],
+            _pad: [0.0; 2],
+        }
+    }
+}
+
+/// A std140 block uploaded into a uniform buffer, ready to be bound to a program
+/// as a named block.
+pub struct UniformBlock<T: Std140> {
+    buffer: UniformBuffer<T::Block>,
+}
+
+impl<T: Std140> UniformBlock<T> {
+    /// Allocate a uniform buffer and upload the initial value.
+    pub fn create<F: glium::backend::Facade>(
+        facade: &F,
+        value: &T,
+    ) -> Result<UniformBlock<T>, CreationError> {
+        let buffer = UniformBuffer::new(facade, value.std140())?;
+
+        Ok(UniformBlock { buffer })
+    }
+
+    /// Re-upload the block's contents. Cheaper than rebuilding a per-call
+    /// uniform tuple when the data changes once per frame but is read by many
+    /// draws.
+    pub fn update(&mut self, value: &T) {
+        self.buffer.write(&value.std140());
+    }
+
+    /// The backing buffer, to be bound to a program as a uniform block.
+    pub fn buffer(&self) -> &UniformBuffer<T::Block> {
+        &self.buffer
+    }
+}
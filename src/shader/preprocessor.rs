@@ -0,0 +1,197 @@
+//! A small preprocessor for the GLSL assembled by the `shaders` modules.
+//!
+//! The `*_core_transform` functions branch on Rust-level booleans like
+//! `have_shadows` and assemble GLSL by string concatenation. Once the
+//! shadow-filter, PBR, and compact-G-buffer options multiply out, that becomes
+//! a combinatorial pile of nearly-identical program builds. This preprocessor
+//! resolves `#include "file"` against a virtual registry and expands
+//! `#define`-style feature flags, and the [`ProgramCache`] deduplicates the
+//! resulting `glium::Program`s by a caller-supplied [`ProgramKey`] (e.g. the
+//! core's name plus its flag preamble); the resolved source itself is not
+//! part of the key.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A virtual filesystem of shader chunks, keyed by the path used in
+/// `#include "path"` directives.
+#[derive(Default)]
+pub struct ShaderRegistry {
+    sources: HashMap<String, String>,
+}
+
+impl ShaderRegistry {
+    pub fn new() -> Self {
+        ShaderRegistry::default()
+    }
+
+    /// Register a chunk under the given include path.
+    pub fn insert(&mut self, path: impl Into<String>, source: impl Into<String>) {
+        self.sources.insert(path.into(), source.into());
+    }
+
+    fn get(&self, path: &str) -> Option<&str> {
+        self.sources.get(path).map(String::as_str)
+    }
+}
+
+/// An error encountered while preprocessing a shader source.
+#[derive(Debug)]
+pub enum PreprocessError {
+    /// An `#include` referenced a path that is not in the registry.
+    MissingInclude { path: String },
+
+    /// An `#include` cycle was detected.
+    IncludeCycle { path: String },
+}
+
+impl std::fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PreprocessError::MissingInclude { path } => {
+                write!(f, "shader include `{}` was not found in the registry", path)
+            }
+            PreprocessError::IncludeCycle { path } => {
+                write!(f, "shader include cycle through `{}`", path)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError {}
+
+/// The set of `#define`d feature flags for one program permutation.
+///
+/// Flags are ordered when rendered so that the same logical set always yields
+/// the same resolved source and cache key, regardless of insertion order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeatureFlags {
+    defines: HashMap<String, String>,
+}
+
+impl FeatureFlags {
+    pub fn new() -> Self {
+        FeatureFlags::default()
+    }
+
+    /// Define `name` with a value, e.g. `PCF_TAPS = "16"`.
+    pub fn define(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.defines.insert(name.into(), value.into());
+        self
+    }
+
+    /// Define `name` as a bare flag (value `1`), the common on/off case.
+    pub fn enable(self, name: impl Into<String>) -> Self {
+        self.define(name, "1")
+    }
+
+    /// The `#define` preamble for this flag set, in a stable order.
+    pub(crate) fn preamble(&self) -> String {
+        let mut names: Vec<&String> = self.defines.keys().collect();
+        names.sort();
+
+        let mut out = String::new();
+        for name in names {
+            out.push_str("#define ");
+            out.push_str(name);
+            out.push(' ');
+            out.push_str(&self.defines[name]);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Resolve `#include` directives and prepend the `#define` preamble for the
+/// given feature flags, producing a single flat GLSL string.
+pub fn preprocess(
+    registry: &ShaderRegistry,
+    source: &str,
+    flags: &FeatureFlags,
+) -> Result<String, PreprocessError> {
+    let mut active = Vec::new();
+    let body = resolve_includes(registry, source, &mut active)?;
+
+    Ok(flags.preamble() + &body)
+}
+
+fn resolve_includes(
+    registry: &ShaderRegistry,
+    source: &str,
+    active: &mut Vec<String>,
+) -> Result<String, PreprocessError> {
+    let mut out = String::new();
+
+    for line in source.lines() {
+        if let Some(path) = parse_include(line) {
+            if active.iter().any(|p| p == path) {
+                return Err(PreprocessError::IncludeCycle {
+                    path: path.to_string(),
+                });
+            }
+
+            let included = registry
+                .get(path)
+                .ok_or_else(|| PreprocessError::MissingInclude {
+                    path: path.to_string(),
+                })?;
+
+            active.push(path.to_string());
+            out.push_str(&resolve_includes(registry, included, active)?);
+            active.pop();
+        } else {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+
+    Ok(out)
+}
+
+/// Parse `#include "path"`, returning the quoted path if the line is an
+/// include directive.
+fn parse_include(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("#include")?.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    rest.strip_suffix('"')
+}
+
+/// Caches compiled programs by permutation key so a given (core,
+/// feature-flag) permutation is only built once.
+///
+/// Callers hold on to the returned `Rc<Program>` past the call that built it
+/// (e.g. as a `DeferredShading` field), so the cache hands out `Rc` clones
+/// rather than borrows tied to its own lifetime.
+#[derive(Default)]
+pub struct ProgramCache {
+    programs: HashMap<ProgramKey, Rc<glium::Program>>,
+}
+
+/// Cache key identifying one compiled program permutation, e.g. the core's
+/// name together with its resolved feature flags.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProgramKey {
+    pub vertex: String,
+    pub fragment: String,
+}
+
+impl ProgramCache {
+    pub fn new() -> Self {
+        ProgramCache::default()
+    }
+
+    /// Return the program for `key`, building it with `build` on the first
+    /// request and returning the cached one afterwards.
+    pub fn get_or_build<F, E>(&mut self, key: ProgramKey, build: F) -> Result<Rc<glium::Program>, E>
+    where
+        F: FnOnce(&ProgramKey) -> Result<glium::Program, E>,
+    {
+        if !self.programs.contains_key(&key) {
+            let program = build(&key)?;
+            self.programs.insert(key.clone(), Rc::new(program));
+        }
+
+        // Safe to unwrap: just inserted above if it was missing.
+        Ok(self.programs.get(&key).unwrap().clone())
+    }
+}